@@ -12,18 +12,98 @@ fn is_whitespace(b: u8) -> bool {
     }
 }
 
+/// A byte-offset range into the rebuilt source buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+
+/// A single diagnostic tied to a span in the source buffer. Diagnostics are
+/// collected rather than raised immediately, so a caller can report every
+/// problem a header has in one pass instead of stopping at the first one.
+#[derive(Debug)]
+pub struct Diagnostic {
+    span: Span,
+    message: String,
+    severity: Severity,
+}
+
+impl Diagnostic {
+    fn error(span: Span, message: impl Into<String>) -> Self {
+        Diagnostic { span, message: message.into(), severity: Severity::Error }
+    }
+
+    /// Maps a byte offset into `(line, col)` (both 0-based) by binary
+    /// searching `line_starts`, the offsets at which each line of the
+    /// rebuilt source begins.
+    fn line_col(offset: usize, line_starts: &[usize]) -> (usize, usize) {
+        let line = match line_starts.binary_search(&offset) {
+            Ok(l) => l,
+            Err(l) => l - 1,
+        };
+        (line, offset - line_starts[line])
+    }
+
+    /// Renders the diagnostic the way codespan-reporting-style tools do:
+    /// an `error: <message>` header, then the offending source line,
+    /// followed by a line of spaces and `^` carets spanning
+    /// `[span.start, span.end)`.
+    fn render(&self, source: &str, line_starts: &[usize]) -> String {
+        let (line_no, col) = Self::line_col(self.span.start, line_starts);
+        let line_end = line_starts.get(line_no + 1).copied().unwrap_or(source.len());
+        let line_start = line_starts[line_no];
+        let line_text = &source[line_start..line_end];
+
+        // `parse_header` prefixes every rebuilt line with a `\r` so each
+        // line start lands on a byte offset of its own; strip that back
+        // off (and shift the caret column to match) so it never shows up
+        // as a literal character in the rendered line.
+        let (line_text, col) = match line_text.strip_prefix('\r') {
+            Some(rest) => (rest, col.saturating_sub(1)),
+            None => (line_text, col),
+        };
+
+        let width = (self.span.end - self.span.start).max(1);
+        let prefix = match self.severity {
+            Severity::Error => "error",
+        };
+
+        format!(
+            "{}: {}\n{}\n{}{}",
+            prefix,
+            self.message,
+            line_text.trim_end_matches(['\r', '\n']),
+            " ".repeat(col),
+            "^".repeat(width),
+        )
+    }
+}
+
 struct Tokenizer<'a> {
     line: &'a str,
     text: &'a [u8],
     last: usize,
-    pos: usize
+    pos: usize,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Tokenizer<'a> {
 
     fn new(line: &'a str) -> Self {
         let text = line.as_bytes();
-        Tokenizer { line, text, last: 0, pos: 0 }
+        Tokenizer { line, text, last: 0, pos: 0, diagnostics: Vec::new() }
     }
 
     fn available(&self) -> bool {
@@ -68,6 +148,10 @@ impl<'a> Tokenizer<'a> {
         &self.line[self.last..self.pos]
     }
 
+    fn span(&self) -> Span {
+        Span::new(self.last, self.pos)
+    }
+
     fn identifier(&mut self) -> &'a str {
         while let Some(b) = self.current() {
             match b {
@@ -90,6 +174,9 @@ impl<'a> Tokenizer<'a> {
         self.slice()
     }
 
+    /// Consumes a `'...'` character literal. If the source ends before the
+    /// closing quote, records a diagnostic instead of panicking and returns
+    /// whatever was consumed so the caller can keep going.
     fn character(&mut self) -> &'a str {
         let mut mask = false;
         while let Some(b) = self.current() {
@@ -102,9 +189,13 @@ impl<'a> Tokenizer<'a> {
                 _ => (),
             }
         }
-        unreachable!()
+        self.diagnostics.push(Diagnostic::error(self.span(), "unterminated character literal"));
+        self.slice()
     }
 
+    /// Consumes a `"..."` string literal. If the source ends before the
+    /// closing quote, records a diagnostic instead of panicking and returns
+    /// whatever was consumed so the caller can keep going.
     fn string(&mut self) -> &'a str {
         let mut mask = false;
         while let Some(b) = self.current() {
@@ -117,13 +208,14 @@ impl<'a> Tokenizer<'a> {
                 _ => (),
             }
         }
-        unreachable!()
+        self.diagnostics.push(Diagnostic::error(self.span(), "unterminated string literal"));
+        self.slice()
     }
 }
 
 
 impl<'a> Iterator for Tokenizer<'a> {
-    type Item = &'a str;
+    type Item = (&'a str, Span);
 
     fn next(&mut self) -> Option<Self::Item> {
         self.skip_whitespace();
@@ -133,21 +225,21 @@ impl<'a> Iterator for Tokenizer<'a> {
         if let Some(b) = self.current() {
             self.step();
 
-            match b {
-                b';' | b',' | b'{' | b'}' | b'[' | b']' | b'(' | b')' | b'?' | b':' => return Some(self.slice()),
+            let token = match b {
+                b';' | b',' | b'{' | b'}' | b'[' | b']' | b'(' | b')' | b'?' | b':' => self.slice(),
                 b'.' => {
                     match (self.current(), self.peek()) {
                         (Some(b'.'), Some(b'.')) => self.step_n(2),
                         _ => (),
                     }
-                    return Some(self.slice());
+                    self.slice()
                 },
                 b'*' | b'^' | b'!' | b'=' | b'/' => {
                     match self.current() {
                         Some(b'=') => self.step(),
                         _ => (),
                     }
-                    return Some(self.slice());
+                    self.slice()
                 },
                 b'&' | b'|' | b'+' => {
                     match self.current() {
@@ -155,7 +247,7 @@ impl<'a> Iterator for Tokenizer<'a> {
                         Some(b2) if b2 == b => self.step(),
                         _ => (),
                     }
-                    return Some(self.slice());
+                    self.slice()
                 },
                 b'<' | b'>' => {
                     match self.current() {
@@ -169,115 +261,350 @@ impl<'a> Iterator for Tokenizer<'a> {
                         },
                         _ => (),
                     }
-                    return Some(self.slice());
+                    self.slice()
                 },
                 b'-' => {
                     match self.current() {
                         Some(b'>') | Some(b'-') | Some(b'=') => self.step(),
                         _ => (),
                     }
-                    return Some(self.slice());
+                    self.slice()
                 },
-                b'_' | b'a'..=b'z' | b'A'..=b'Z' => return Some(self.identifier()),
-                b'0'..=b'9' => return Some(self.number()),
-                b'"' => return Some(self.string()),
-                b'\'' => return Some(self.character()),
-                _ => unreachable!(),
-            }
+                b'_' | b'a'..=b'z' | b'A'..=b'Z' => self.identifier(),
+                b'0'..=b'9' => self.number(),
+                b'"' => self.string(),
+                b'\'' => self.character(),
+                _ => {
+                    self.diagnostics.push(Diagnostic::error(self.span(), format!("unexpected byte {:#04x}", b)));
+                    self.slice()
+                },
+            };
+            return Some((token, self.span()));
         }
         None
     }
 }
 
+/// A C type as parsed from a declarator, independent of how it will
+/// eventually be rendered into Rust syntax.
+#[derive(Debug, Clone)]
+pub enum Type {
+    /// A plain named type, e.g. `int`, `unsigned long`, a struct tag.
+    Named(String),
+    /// `T *`
+    Pointer(Box<Type>),
+    /// `const T`
+    Const(Box<Type>),
+    /// `T name[dim]`, keeping the dimension as source text since it may be
+    /// a constant expression rather than a literal.
+    Array(Box<Type>, String),
+    /// `ret (*)(params)`
+    FunctionPointer { ret: Box<Type>, params: Vec<Type> },
+}
+
 #[derive(Debug)]
-struct Member {
+pub struct Field {
+    /// Empty for an anonymous bitfield (`unsigned : 4;`), the standard C
+    /// idiom for padding/alignment: it still occupies bits in its storage
+    /// unit but has nothing to generate an accessor for.
     ident: String,
-    type_: String,
-    dims: Option<String>,
+    ty: Type,
+    /// Bit width for a bitfield member (`unsigned flags : 3;`), `None` for
+    /// an ordinary member.
+    bits: Option<u32>,
 }
 
 #[derive(Debug)]
-struct Value {
+pub struct EnumVariant {
     ident: String,
     value: Option<String>,
 }
 
-enum Stmt {
-    Alias(String),
-    Enum(Vec<Value>),
-    Struct(Vec<Member>),
+#[derive(Debug)]
+pub struct Param {
+    name: Option<String>,
+    ty: Type,
 }
 
+/// A top-level declaration. The AST is the single source of truth the
+/// emitter consumes; parsing stays independent of code generation.
+#[derive(Debug)]
+pub enum Decl {
+    Typedef(Type),
+    Struct { tag: Option<String>, fields: Vec<Field> },
+    Union { tag: Option<String>, fields: Vec<Field> },
+    Enum { tag: Option<String>, variants: Vec<EnumVariant> },
+    Function { ret: Type, params: Vec<Param>, variadic: bool },
+    Extern(Type),
+}
 
-fn try_parse_struct<'a>(stmt: &'a [&'a str]) -> Option<(Option<&'a str>, &'a [&'a str])> {
-    let l = stmt.len();
-    match stmt {
-        ["struct", "{", .., "}"] => Some((None, &stmt[2..l-1])),
-        ["struct", tag, "{", .., "}"] => Some((Some(tag), &stmt[3..l-1])),
-        _ => None,
+/// Parses a flat run of type tokens (no declarator name) into a `Type`,
+/// building pointer layers left to right so `char **` and `const char *`
+/// nest correctly.
+fn parse_type_tokens(tokens: &[&str]) -> Type {
+    let mut i = 0;
+
+    let is_const_base = tokens.get(i) == Some(&"const");
+    if is_const_base {
+        i += 1;
     }
+
+    let name_start = i;
+    while tokens.get(i).map_or(false, |&t| t != "*") {
+        i += 1;
+    }
+
+    let mut ty = Type::Named(tokens[name_start..i].join(" "));
+    if is_const_base {
+        ty = Type::Const(Box::new(ty));
+    }
+
+    while tokens.get(i) == Some(&"*") {
+        i += 1;
+        ty = Type::Pointer(Box::new(ty));
+        if tokens.get(i) == Some(&"const") {
+            i += 1;
+        }
+    }
+
+    ty
 }
 
-fn try_parse_member<'a>(stmt: &'a [&'a str]) -> Option<Member> {
-    let l = stmt.len();
-    match stmt {
-        [.., "]"] => if let Some(pos) = stmt.iter().position(|&s| s == "[") {
-            let ident = stmt[pos - 1].into();
-            let type_ = (*&stmt[..pos - 1].join("~")).clone();
-            let dims = (*&stmt[pos + 1..l - 1].join("~")).clone();
-            Some(Member { ident, type_, dims: Some(dims) })
-        } else {
-            None
-        },
-        [] => None,
-        _ => {
-            let ident = stmt[l - 1].into();
-            let type_ = (*&stmt[..l - 1].join("~")).clone();
-            Some(Member { ident, type_, dims: None })
+/// Splits `tokens` on top-level occurrences of `sep`, ignoring `sep`
+/// nested inside `(`/`[`/`{`.
+fn split_top_level<'a>(tokens: &'a [&'a str], sep: &str) -> Vec<&'a [&'a str]> {
+    let mut parts = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    for (i, &t) in tokens.iter().enumerate() {
+        match t {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth -= 1,
+            _ if depth == 0 && t == sep => {
+                parts.push(&tokens[start..i]);
+                start = i + 1;
+            },
+            _ => (),
         }
     }
+    parts.push(&tokens[start..]);
+    parts
 }
 
-fn parse_members<'a>(stmt: &'a [&'a str]) -> Vec<Member> {
-    stmt.split(|&m| m == ";").filter_map(try_parse_member).collect()
+/// Parses a comma-separated list of type tokens, e.g. the parameter list
+/// of a function-pointer declarator. Each part may carry a trailing
+/// parameter name (`void (*cb)(int code)` is as common as `(int)`), so it's
+/// stripped the same way `parse_param_list` strips one before translating
+/// the type.
+fn parse_type_list(tokens: &[&str]) -> Vec<Type> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    split_top_level(tokens, ",").into_iter()
+        .map(|part| parse_type_tokens(split_param_declarator(part).0))
+        .collect()
 }
 
-fn try_parse_enum<'a>(stmt: &'a [&'a str]) -> Option<(Option<&'a str>, &'a [&'a str])> {
-    let l = stmt.len();
-    match stmt {
-        ["enum", "{", .., "}"] => Some((None, &stmt[2..l-1])),
-        ["enum", tag, "{", .., "}"] => Some((Some(tag), &stmt[3..l-1])),
-        _ => None,
+/// Finds a `( * ident ) ( params )` function-pointer declarator anywhere in
+/// `tokens` and, if present, returns its pieces.
+fn find_function_pointer_declarator<'a>(tokens: &'a [&'a str]) -> Option<(&'a [&'a str], &'a str, &'a [&'a str])> {
+    for (i, w) in tokens.windows(2).enumerate() {
+        if w == ["(", "*"] {
+            let ident_pos = i + 2;
+            let ident = *tokens.get(ident_pos)?;
+            if tokens.get(ident_pos + 1) != Some(&")") {
+                continue;
+            }
+            if tokens.get(ident_pos + 2) != Some(&"(") {
+                continue;
+            }
+            if tokens.last() != Some(&")") {
+                continue;
+            }
+            let ret = &tokens[..i];
+            let params = &tokens[ident_pos + 3..tokens.len() - 1];
+            return Some((ret, ident, params));
+        }
     }
+    None
 }
 
-fn try_parse_value<'a>(stmt: &'a [&'a str]) -> Option<Value> {
-    match stmt {
-        [name, "=", ..] => {
-            let ident = (*name).into();
-            let value = (*&stmt[2..].join("~")).clone();
-            Some(Value { ident, value: Some(value) })
-        },
-        [name] => {
-            let ident = (*name).into();
-            Some(Value { ident, value: None })
-        },
-        _ => None,
+/// Parses a single struct/union member (the tokens between two `;`) into a
+/// `Field`, handling the plain-declarator, array-declarator,
+/// function-pointer-declarator, and bitfield (`ident : width`) shapes.
+fn parse_member_tokens(tokens: &[&str]) -> Option<Field> {
+    if tokens.is_empty() {
+        return None;
+    }
+
+    if let Some(colon) = tokens.iter().position(|&t| t == ":") {
+        let width: u32 = tokens[colon + 1..].join("").parse().ok()?;
+        // An anonymous bitfield (`unsigned : 4;`) has no identifier before
+        // `:` at all, which is the same name-vs-type ambiguity a parameter
+        // declarator has, so reuse that same split.
+        let (ty_tokens, name) = split_param_declarator(&tokens[..colon]);
+        let ty = parse_type_tokens(ty_tokens);
+        return Some(Field { ident: name.unwrap_or_default(), ty, bits: Some(width) });
+    }
+
+    if let Some((ret, ident, params)) = find_function_pointer_declarator(tokens) {
+        let ty = Type::FunctionPointer { ret: Box::new(parse_type_tokens(ret)), params: parse_type_list(params) };
+        return Some(Field { ident: ident.into(), ty, bits: None });
+    }
+
+    let l = tokens.len();
+    if tokens[l - 1] == "]" {
+        let open = tokens.iter().position(|&t| t == "[")?;
+        let ident = tokens[open - 1].to_string();
+        let dim = tokens[open + 1..l - 1].join(" ");
+        let ty = Type::Array(Box::new(parse_type_tokens(&tokens[..open - 1])), dim);
+        return Some(Field { ident, ty, bits: None });
     }
+
+    let ident = tokens[l - 1].to_string();
+    let ty = parse_type_tokens(&tokens[..l - 1]);
+    Some(Field { ident, ty, bits: None })
 }
 
-fn parse_values<'a>(stmt: &'a [&'a str]) -> Vec<Value> {
-    stmt.split(|&m| m == ",").filter_map(try_parse_value).collect()
+fn parse_member_list(tokens: &[&str]) -> Vec<Field> {
+    tokens.split(|&t| t == ";").filter_map(parse_member_tokens).collect()
 }
 
-fn try_parse_typedef<'a>(stmt: &'a [&'a str]) -> Option<(&'a str, &'a [&'a str])> {
-    let l = stmt.len();
-    match stmt {
-        ["typedef", .., name] => Some((name, &stmt[1..l - 1])),
+fn parse_variant_tokens(tokens: &[&str]) -> Option<EnumVariant> {
+    match tokens {
+        [name, "=", rest @ ..] => Some(EnumVariant { ident: (*name).into(), value: Some(rest.join(" ")) }),
+        [name] => Some(EnumVariant { ident: (*name).into(), value: None }),
         _ => None,
     }
 }
 
+fn parse_variant_list(tokens: &[&str]) -> Vec<EnumVariant> {
+    split_top_level(tokens, ",").into_iter().filter_map(parse_variant_tokens).collect()
+}
+
+/// Parses the right-hand side of a plain `typedef <type> <name>;` (i.e. one
+/// that isn't a `struct`/`enum` body) into its `Type` and declared name,
+/// including the function-pointer-typedef shape.
+fn parse_typedef_rhs(tokens: &[&str]) -> Option<(Type, String)> {
+    if let Some((ret, ident, params)) = find_function_pointer_declarator(tokens) {
+        let ty = Type::FunctionPointer { ret: Box::new(parse_type_tokens(ret)), params: parse_type_list(params) };
+        return Some((ty, ident.into()));
+    }
+
+    let l = tokens.len();
+    if l == 0 {
+        return None;
+    }
+    let name = tokens[l - 1].to_string();
+    let ty = parse_type_tokens(&tokens[..l - 1]);
+    Some((ty, name))
+}
+
+/// Owns the token stream for one top-level statement and walks it with
+/// genuine recursive descent (`peek`/`advance`/`expect`), rather than the
+/// slice patterns the tool used to rely on.
+struct Parser<'a> {
+    tokens: &'a [&'a str],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(tokens: &'a [&'a str]) -> Self {
+        Parser { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn advance(&mut self) -> Option<&'a str> {
+        let t = self.peek();
+        if t.is_some() {
+            self.pos += 1;
+        }
+        t
+    }
+
+    fn expect(&mut self, tok: &str) -> Option<&'a str> {
+        if self.peek() == Some(tok) {
+            self.advance()
+        } else {
+            None
+        }
+    }
+
+    fn remaining(&self) -> &'a [&'a str] {
+        &self.tokens[self.pos..]
+    }
+
+    /// Consumes an optional tag identifier before a `struct`/`enum` body,
+    /// i.e. the `Foo` in `struct Foo { ... }`.
+    fn parse_optional_tag(&mut self) -> Option<String> {
+        match self.peek() {
+            Some("{") | None => None,
+            Some(t) => {
+                self.advance();
+                Some(t.to_string())
+            }
+        }
+    }
+
+    /// Consumes a balanced `{ ... }` body and returns its interior tokens,
+    /// without requiring every brace-using construct in this tool to be
+    /// re-taught how to find its own closing brace.
+    fn parse_braced_body(&mut self) -> Option<&'a [&'a str]> {
+        self.expect("{")?;
+        let start = self.pos;
+        let mut balance = 1;
+        while balance > 0 {
+            match self.advance()? {
+                "{" => balance += 1,
+                "}" => balance -= 1,
+                _ => (),
+            }
+        }
+        Some(&self.tokens[start..self.pos - 1])
+    }
+
+    /// Parses one top-level `typedef ...;` statement into its declared
+    /// name and `Decl`. Bare (non-typedef) struct/union/enum declarations
+    /// and everything else are left for the caller, same as before.
+    fn parse_typedef(&mut self) -> Option<(String, Decl)> {
+        self.expect("typedef")?;
+
+        match self.peek() {
+            Some("struct") => {
+                self.advance();
+                let tag = self.parse_optional_tag();
+                let body = self.parse_braced_body()?;
+                let fields = parse_member_list(body);
+                let name = self.advance()?.to_string();
+                Some((name, Decl::Struct { tag, fields }))
+            },
+            Some("union") => {
+                self.advance();
+                let tag = self.parse_optional_tag();
+                let body = self.parse_braced_body()?;
+                let fields = parse_member_list(body);
+                let name = self.advance()?.to_string();
+                Some((name, Decl::Union { tag, fields }))
+            },
+            Some("enum") => {
+                self.advance();
+                let tag = self.parse_optional_tag();
+                let body = self.parse_braced_body()?;
+                let variants = parse_variant_list(body);
+                let name = self.advance()?.to_string();
+                Some((name, Decl::Enum { tag, variants }))
+            },
+            _ => {
+                let (ty, name) = parse_typedef_rhs(self.remaining())?;
+                Some((name, Decl::Typedef(ty)))
+            },
+        }
+    }
+}
+
 fn try_parse_function<'a>(stmt: &'a [&'a str]) -> Option<(&'a [&'a str], &'a str, &'a [&'a str])> {
     let l = stmt.len();
     match stmt {
@@ -290,19 +617,6 @@ fn try_parse_function<'a>(stmt: &'a [&'a str]) -> Option<(&'a [&'a str], &'a str
     }
 }
 
-fn try_parse_function_type<'a>(stmt: &'a [&'a str]) -> Option<(&'a [&'a str], &'a str, &'a [&'a str])> {
-    match stmt {
-        [.., ")"] => {
-            let braces = stmt.iter().enumerate().filter(|&(_, &c)| c == "(" || c == ")").collect::<Vec<_>>();
-            match braces.as_slice() {
-                [(a, _), (b, _), (c, _), (d, _)] => Some((&stmt[..*a], stmt[*b - 1], &stmt[*c + 1..*d])),
-                _ => None,
-            }
-        },
-        _ => None,
-    }
-}
-
 fn try_parse_decl<'a>(stmt: &'a [&'a str]) -> Option<&'a [&'a str]> {
     match stmt {
         ["__pragma", ..] | ["__declspec", ..] => Some(stmt),
@@ -317,49 +631,167 @@ fn try_parse_extern<'a>(stmt: &'a [&'a str]) -> Option<&'a [&'a str]> {
     }
 }
 
-fn print_stmt(stmt: &[&str]) {
-    println!("{}", stmt.join(" ~ "));
+/// Recognizes a bare (non-typedef) `struct`/`union`/`enum` declaration: a
+/// full body or just a forward declaration of the tag. A tagged
+/// *returning* declarator (`struct Foo *make_foo(void);`) doesn't match.
+fn is_bare_tagged_body_decl(stmt: &[&str]) -> bool {
+    matches!(stmt.first(), Some(&"struct") | Some(&"union") | Some(&"enum"))
+        && (stmt.last() == Some(&"}") || (stmt.len() == 2 && is_ident(stmt[1])))
 }
 
-fn parse_statement(stmt: &[&str], types: &mut HashMap<String, Stmt>) {
+/// The tag of a struct/union/enum declaration's body, if it has one.
+fn tag_of(decl: &Decl) -> Option<&str> {
+    match decl {
+        Decl::Struct { tag, .. } | Decl::Union { tag, .. } | Decl::Enum { tag, .. } => tag.as_deref(),
+        _ => None,
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => chars.all(|c| c == '_' || c.is_ascii_alphanumeric()),
+        _ => false,
+    }
+}
+
+/// Splits a single function parameter's tokens into its type and trailing
+/// name, if any. A multi-word `KNONW_ALIASES` builtin spelling (`unsigned
+/// long`, ...) is never mistaken for type-plus-name.
+fn split_param_declarator<'a>(tokens: &'a [&'a str]) -> (&'a [&'a str], Option<String>) {
+    let l = tokens.len();
+    if l > 1 && KNONW_ALIASES.iter().any(|&(c, _)| c == tokens.join(" ")) {
+        return (tokens, None);
+    }
+    if l > 1 && is_ident(tokens[l - 1]) {
+        (&tokens[..l - 1], Some(tokens[l - 1].to_string()))
+    } else {
+        (tokens, None)
+    }
+}
+
+/// Parses a function's comma-separated parameter token list, recognizing
+/// a lone `void` as "no parameters" and a trailing `...` (already
+/// tokenized as a single token) as a variadic marker rather than a param.
+fn parse_param_list(tokens: &[&str]) -> (Vec<Param>, bool) {
+    let mut params = Vec::new();
+    let mut variadic = false;
+
+    for part in split_top_level(tokens, ",") {
+        match part {
+            [] => (),
+            ["void"] if params.is_empty() => (),
+            ["..."] => variadic = true,
+            _ => {
+                if let Some((ret, ident, fp_params)) = find_function_pointer_declarator(part) {
+                    let ty = Type::FunctionPointer { ret: Box::new(parse_type_tokens(ret)), params: parse_type_list(fp_params) };
+                    params.push(Param { name: Some(ident.into()), ty });
+                } else {
+                    let (type_tokens, name) = split_param_declarator(part);
+                    params.push(Param { name, ty: parse_type_tokens(type_tokens) });
+                }
+            },
+        }
+    }
+
+    (params, variadic)
+}
+
+/// Drops any bitfield whose declared width doesn't fit in its own base
+/// type's bit capacity (`unsigned big : 40;`), recording a `Diagnostic`
+/// instead of letting an oversized width reach the emitter, where it would
+/// produce a mask literal rustc rejects outright.
+fn validate_bitfields(fields: Vec<Field>, span: Span, diagnostics: &mut Vec<Diagnostic>) -> Vec<Field> {
+    fields.into_iter().filter(|field| {
+        let Some(width) = field.bits else { return true };
+        let capacity = bit_width_of(&to_rust_type(&field.ty));
+        if width > capacity {
+            diagnostics.push(Diagnostic::error(span, format!(
+                "bitfield '{}' has width {} but its type only holds {} bits", field.ident, width, capacity
+            )));
+            false
+        } else {
+            true
+        }
+    }).collect()
+}
+
+fn parse_statement(stmt: &[&str], spans: &[Span], types: &mut HashMap<String, Decl>, diagnostics: &mut Vec<Diagnostic>) {
     let l = stmt.len();
-    let stmt = if let [.., ";"] = stmt {
-        &stmt[..l - 1]
+    let (stmt, spans) = if let [.., ";"] = stmt {
+        (&stmt[..l - 1], &spans[..l - 1])
     } else {
-        stmt
+        (stmt, spans)
     };
 
-    if let Some((name, typedef)) = try_parse_typedef(stmt) {
-        if let Some((_tag, members)) = try_parse_struct(typedef) {
-            let members = parse_members(members);
-            types.insert(name.into(), Stmt::Struct(members));
-        } else if let Some((_tag, values)) = try_parse_enum(typedef) {
-            let values = parse_values(values);
-            types.insert(name.into(), Stmt::Enum(values));
-        } else if let Some((_ret, _name, _params)) = try_parse_function_type(&stmt[1..]) {
-            // print_stmt(stmt);
-            // println!("{} {:?}: {:?}", name, params, ret);
-        } else {
-            // print_stmt(stmt);
-            // println!("TYPE {} = {:?}", name, typedef);
-            types.insert(name.into(), Stmt::Alias(typedef.join("~")));
+    // An `extern` prefix just marks linkage; strip it before looking for a
+    // function declaration so it doesn't get swallowed into the return type.
+    let (has_extern, fn_stmt) = match try_parse_extern(stmt) {
+        Some(rest) => (true, rest),
+        None => (false, stmt),
+    };
+
+    if stmt.first() == Some(&"typedef") {
+        if let Some((name, decl)) = Parser::new(stmt).parse_typedef() {
+            // A self-reference inside the body is spelled with the tag, not
+            // the typedef'd name, so alias the tag to it too.
+            if let Some(tag) = tag_of(&decl) {
+                if tag != name {
+                    types.entry(tag.to_string()).or_insert_with(|| Decl::Typedef(Type::Named(name.clone())));
+                }
+            }
+            let span = Span::new(spans[0].start, spans[spans.len() - 1].end);
+            let decl = match decl {
+                Decl::Struct { tag, fields } => Decl::Struct { tag, fields: validate_bitfields(fields, span, diagnostics) },
+                Decl::Union { tag, fields } => Decl::Union { tag, fields: validate_bitfields(fields, span, diagnostics) },
+                other => other,
+            };
+            types.insert(name, decl);
         }
-    } else if let Some((_tag, _members)) = try_parse_struct(stmt) {
-    } else if let Some((_ret, _name, _params)) = try_parse_function(stmt) {
-        // println!("{} {:?}: {:?}", name, params, ret);
+    } else if is_bare_tagged_body_decl(stmt) {
+        // bare struct/union/enum declarations aren't typedef'd to a name,
+        // so there's nothing to register yet.
+    } else if let Some((ret, name, param_tokens)) = try_parse_function(fn_stmt) {
+        let (params, variadic) = parse_param_list(param_tokens);
+        types.insert(name.into(), Decl::Function { ret: parse_type_tokens(ret), params, variadic });
     } else if let Some(_) = try_parse_decl(stmt) {
-    } else if let Some(_) = try_parse_extern(stmt) {
-    } else {
-        print_stmt(stmt);
+    } else if has_extern {
+        if let Some(field) = parse_member_tokens(fn_stmt) {
+            types.insert(field.ident, Decl::Extern(field.ty));
+        }
+    } else if !stmt.is_empty() {
+        let span = Span::new(spans[0].start, spans[spans.len() - 1].end);
+        diagnostics.push(Diagnostic::error(span, format!("unrecognized statement: {}", stmt.join(" "))));
     }
 }
 
 
-fn parse<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, Stmt>> {
+/// The result of parsing a header: every declaration keyed by its declared
+/// name, plus whatever diagnostics were accumulated along the way. Parsing
+/// doesn't abort on the first problem, so even an `Ast` with diagnostics may
+/// still have usable declarations a caller can choose to emit.
+#[derive(Debug)]
+pub struct Ast {
+    pub decls: HashMap<String, Decl>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// The rebuilt source buffer, kept around so diagnostics can be
+    /// rendered with [`Diagnostic::render`] after parsing returns.
+    pub source: String,
+    /// Byte offsets at which each line of `source` begins, as built while
+    /// rebuilding the source; needed by [`Diagnostic::render`].
+    pub line_starts: Vec<usize>,
+}
+
+/// Parses a preprocessed header (as produced by `cl /P`) into an [`Ast`].
+/// This is the library entry point: callers decide what to do with the
+/// result, whether that's feeding it to [`emit`], snapshot-testing it, or
+/// inspecting `diagnostics` directly.
+pub fn parse_header<P: AsRef<Path>>(path: P) -> io::Result<Ast> {
     let file = File::open(path)?;
     let reader = io::BufReader::new(file);
 
     let mut source = String::new();
+    let mut line_starts = Vec::new();
 
     for line in reader.lines() {
         let line = line?;
@@ -368,17 +800,20 @@ fn parse<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, Stmt>> {
             continue;
         }
 
+        line_starts.push(source.len());
         source.push('\r');
         source.push_str(&line);
     }
-    
+
     let mut balance = 0;
     let mut statement = Vec::new();
+    let mut spans = Vec::new();
     let mut types = HashMap::new();
 
-    let tokenizer = Tokenizer::new(&source);
-    for token in tokenizer {
+    let mut tokenizer = Tokenizer::new(&source);
+    while let Some((token, span)) = tokenizer.next() {
         statement.push(token);
+        spans.push(span);
         match token {
             "{" | "[" | "(" => balance += 1,
             "}" | "]" | ")" => {
@@ -386,88 +821,344 @@ fn parse<P: AsRef<Path>>(path: P) -> io::Result<HashMap<String, Stmt>> {
                 if balance == 0 {
                     match statement[0] {
                         "__pragma" | "__declspec" => {
-                            parse_statement(&statement, &mut types);
+                            parse_statement(&statement, &spans, &mut types, &mut tokenizer.diagnostics);
                             statement.clear();
+                            spans.clear();
                         },
                         _ => (),
                     }
                 }
             },
             ";" if balance == 0 => {
-                parse_statement(&statement, &mut types);
+                parse_statement(&statement, &spans, &mut types, &mut tokenizer.diagnostics);
                 statement.clear();
+                spans.clear();
             },
             _ => (),
         }
     }
 
-    Ok(types)
+    Ok(Ast { decls: types, diagnostics: tokenizer.diagnostics, source, line_starts })
 }
 
+// Primitive C spellings that don't otherwise appear as a `typedef` in the
+// header, plus the project's known `*_T` aliases, mapped to the Rust type
+// each should translate to wherever it's referenced. `translate_named` wires this
+// table through the declarator translation below instead of pre-seeding
+// `types` with fake aliases, so e.g. `uint32_T` and `unsigned int` both
+// resolve inline at the point of use.
+static KNONW_ALIASES: &'static [(&'static str, &'static str)] = &[
+    ("uint32_T", "u32"), ("int32_T", "i32"), ("boolean_T", "u8"), ("uint16_T", "u16"), ("real_T", "f64"),
+    ("void", "std::os::raw::c_void"),
+    ("char", "std::os::raw::c_char"),
+    ("signed char", "std::os::raw::c_schar"),
+    ("unsigned char", "std::os::raw::c_uchar"),
+    ("short", "std::os::raw::c_short"),
+    ("short int", "std::os::raw::c_short"),
+    ("unsigned short", "std::os::raw::c_ushort"),
+    ("unsigned short int", "std::os::raw::c_ushort"),
+    ("int", "std::os::raw::c_int"),
+    ("unsigned", "std::os::raw::c_uint"),
+    ("unsigned int", "std::os::raw::c_uint"),
+    ("long", "std::os::raw::c_long"),
+    ("long int", "std::os::raw::c_long"),
+    ("unsigned long", "std::os::raw::c_ulong"),
+    ("unsigned long int", "std::os::raw::c_ulong"),
+    ("long long", "std::os::raw::c_longlong"),
+    ("long long int", "std::os::raw::c_longlong"),
+    ("unsigned long long", "std::os::raw::c_ulonglong"),
+    ("unsigned long long int", "std::os::raw::c_ulonglong"),
+    ("float", "f32"),
+    ("double", "f64"),
+];
+
+/// Strips a leading `struct`/`union`/`enum` keyword off a `Type::Named`'s
+/// text, e.g. `struct Node_` -> `Node_`.
+fn strip_tag_keyword(name: &str) -> &str {
+    for keyword in ["struct ", "union ", "enum "] {
+        if let Some(rest) = name.strip_prefix(keyword) {
+            return rest;
+        }
+    }
+    name
+}
 
-fn lookup(name: &str, types: &HashMap<String, Stmt>, known: &mut HashSet<String>) -> bool {
-    if known.contains(name) {
-        return true;
+fn translate_named(name: &str) -> String {
+    let name = strip_tag_keyword(name);
+    match KNONW_ALIASES.iter().find(|&&(c, _)| c == name) {
+        Some(&(_, rust)) => rust.to_string(),
+        None => name.to_string(),
     }
+}
 
-    known.insert(name.into());
+fn is_void(ty: &Type) -> bool {
+    matches!(ty, Type::Named(name) if strip_tag_keyword(name) == "void")
+}
 
-    if let Some(t) = types.get(name) {
-        match t {
-            Stmt::Alias(alias) => {
-                println!("pub type {} = {};\n", name, alias);
-            },
-            Stmt::Enum(values) => {
-                println!("#[repr(C)]\npub enum {} {{", name);
-                for value in values {
-                    match value.value {
-                        Some(ref v) => println!("\t{}={},", value.ident, v),
-                        None => println!("\t{},", value.ident),
-                    }
-                }
-                println!("}}\n");
+/// Translates a `Type` into the Rust source text it should be emitted as.
+/// Recurses, so `char **` comes out as `*mut *mut c_char`.
+fn to_rust_type(ty: &Type) -> String {
+    match ty {
+        Type::Named(name) => translate_named(name),
+        Type::Const(inner) => to_rust_type(inner),
+        Type::Pointer(inner) => match &**inner {
+            Type::Const(of) => format!("*const {}", to_rust_type(of)),
+            _ => format!("*mut {}", to_rust_type(inner)),
+        },
+        Type::Array(inner, dim) => format!("[{}; {}]", to_rust_type(inner), dim),
+        Type::FunctionPointer { ret, params } => {
+            let params = params.iter().map(|p| to_rust_type(p)).collect::<Vec<_>>().join(", ");
+            match is_void(ret) {
+                true => format!("Option<extern \"C\" fn({})>", params),
+                false => format!("Option<extern \"C\" fn({}) -> {}>", params, to_rust_type(ret)),
+            }
+        },
+    }
+}
+
+/// Bit width backing a bitfield's storage field, used to know when a run
+/// of consecutive bitfields of the same type has filled its storage unit
+/// and needs to spill into a new one.
+fn bit_width_of(rust_ty: &str) -> u32 {
+    match rust_ty {
+        "u8" | "i8" | "std::os::raw::c_char" | "std::os::raw::c_schar" | "std::os::raw::c_uchar" => 8,
+        "u16" | "i16" | "std::os::raw::c_short" | "std::os::raw::c_ushort" => 16,
+        "u64" | "i64" | "std::os::raw::c_longlong" | "std::os::raw::c_ulonglong" => 64,
+        _ => 32,
+    }
+}
+
+/// Whether a bitfield's base type is signed, so its getter knows to
+/// sign-extend (`int flag : 3;` has a negative half, unlike `unsigned`).
+fn is_signed_rust_type(rust_ty: &str) -> bool {
+    matches!(rust_ty,
+        "i8" | "i16" | "i32" | "i64"
+        | "std::os::raw::c_char" | "std::os::raw::c_schar" | "std::os::raw::c_short"
+        | "std::os::raw::c_int" | "std::os::raw::c_long" | "std::os::raw::c_longlong")
+}
+
+/// Renders the getter/setter pair for one bitfield packed into `storage`,
+/// masking and shifting against its `offset`/`width` within that field. A
+/// signed base type is sign-extended by shifting the masked value up to
+/// the type's MSB and back down with an arithmetic shift.
+fn render_bitfield_accessor(storage: &str, rust_ty: &str, ident: &str, offset: u32, width: u32) -> String {
+    let mask: u64 = if width >= 64 { u64::MAX } else { (1u64 << width) - 1 };
+    let shift = bit_width_of(rust_ty) - width;
+    let getter_body = if is_signed_rust_type(rust_ty) && shift > 0 {
+        format!("(((self.{storage} >> {offset}) & {mask}) as {ty}) << {shift} >> {shift}", storage = storage, offset = offset, mask = mask, ty = rust_ty, shift = shift)
+    } else {
+        format!("((self.{storage} >> {offset}) & {mask}) as {ty}", storage = storage, offset = offset, mask = mask, ty = rust_ty)
+    };
+    format!(
+        "\tpub fn {ident}(&self) -> {ty} {{\n\t\t{getter_body}\n\t}}\n\n\tpub fn set_{ident}(&mut self, v: {ty}) {{\n\t\tself.{storage} = (self.{storage} & !(({mask} as {ty}) << {offset})) | ((v & {mask} as {ty}) << {offset});\n\t}}\n",
+        ident = ident, ty = rust_ty, storage = storage, offset = offset, mask = mask, getter_body = getter_body,
+    )
+}
+
+/// Emits a `struct`/`union` body, packing consecutive bitfields of the
+/// same base type into a single backing integer field plus accessor
+/// methods, since Rust has no native bitfield support.
+fn emit_struct_like(keyword: &str, name: &str, fields: &[Field], out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "#[repr(C)]\npub {} {} {{", keyword, name)?;
+
+    let mut accessors = Vec::new();
+    let mut storage_index = 0;
+    let mut i = 0;
+    while i < fields.len() {
+        let Some(width) = fields[i].bits else {
+            writeln!(out, "\tpub {}: {},", fields[i].ident, to_rust_type(&fields[i].ty))?;
+            i += 1;
+            continue;
+        };
+
+        let rust_ty = to_rust_type(&fields[i].ty);
+        let capacity = bit_width_of(&rust_ty);
+        let mut members = vec![(fields[i].ident.clone(), 0u32, width)];
+        let mut used = width;
+        let mut j = i + 1;
+        while j < fields.len() && fields[j].bits.is_some_and(|w| to_rust_type(&fields[j].ty) == rust_ty && used + w <= capacity) {
+            let w = fields[j].bits.unwrap();
+            members.push((fields[j].ident.clone(), used, w));
+            used += w;
+            j += 1;
+        }
+
+        let storage = format!("_bitfield{}", storage_index);
+        storage_index += 1;
+        writeln!(out, "\tpub {}: {},", storage, rust_ty)?;
+        for (ident, offset, width) in &members {
+            if ident.is_empty() {
+                continue;
+            }
+            accessors.push(render_bitfield_accessor(&storage, &rust_ty, ident, *offset, *width));
+        }
+        i = j;
+    }
+    writeln!(out, "}}\n")?;
+
+    if !accessors.is_empty() {
+        writeln!(out, "impl {} {{", name)?;
+        for accessor in &accessors {
+            writeln!(out, "{}", accessor)?;
+        }
+        writeln!(out, "}}\n")?;
+    }
+    Ok(())
+}
+
+/// Collects the names of every other declared type a `Decl` references, by
+/// walking into `Type::Named` wherever it appears.
+fn referenced_names(decl: &Decl, out: &mut Vec<String>) {
+    fn walk(ty: &Type, out: &mut Vec<String>) {
+        match ty {
+            Type::Named(name) => out.push(strip_tag_keyword(name).to_string()),
+            Type::Pointer(inner) | Type::Const(inner) | Type::Array(inner, _) => walk(inner, out),
+            Type::FunctionPointer { ret, params } => {
+                walk(ret, out);
+                params.iter().for_each(|p| walk(p, out));
             },
-            Stmt::Struct(members) => {
-                for member in members {
-                    lookup(&member.type_, types, known);
-                }
+        }
+    }
 
-                println!("#[repr(C)]\npub struct {} {{", name);
-                for member in members {
-                    match member.dims {
-                        Some(ref size) => println!("\tpub {}: [{}; {}],", member.ident, member.type_, size),
-                        None => println!("\tpub {}: {},", member.ident, member.type_),
-                    }
+    match decl {
+        Decl::Typedef(ty) | Decl::Extern(ty) => walk(ty, out),
+        Decl::Struct { fields, .. } | Decl::Union { fields, .. } => {
+            fields.iter().for_each(|f| walk(&f.ty, out));
+        },
+        Decl::Enum { .. } | Decl::Function { .. } => (),
+    }
+}
+
+/// Orders every declaration other than functions and extern variables so
+/// each type is emitted only after
+/// every type it references — a depth-first post-order walk over the
+/// dependency graph `referenced_names` builds, starting from names in
+/// sorted order so the result doesn't depend on `HashMap` iteration order.
+/// A back-edge to a name still on the current path means a reference cycle
+/// (e.g. two structs pointing at each other); the walk just stops
+/// following that edge rather than emitting a forward declaration.
+fn dependency_order(types: &HashMap<String, Decl>) -> Vec<String> {
+    fn visit(name: &str, types: &HashMap<String, Decl>, visited: &mut HashSet<String>, on_path: &mut HashSet<String>, out: &mut Vec<String>) {
+        if visited.contains(name) || on_path.contains(name) {
+            return;
+        }
+        let Some(decl) = types.get(name) else { return };
+        if matches!(decl, Decl::Function { .. } | Decl::Extern(_)) {
+            return;
+        }
+
+        on_path.insert(name.to_string());
+        let mut deps = Vec::new();
+        referenced_names(decl, &mut deps);
+        for dep in deps {
+            visit(&dep, types, visited, on_path, out);
+        }
+        on_path.remove(name);
+
+        visited.insert(name.to_string());
+        out.push(name.to_string());
+    }
+
+    let mut names: Vec<&String> = types.keys().collect();
+    names.sort();
+
+    let mut visited = HashSet::new();
+    let mut on_path = HashSet::new();
+    let mut out = Vec::new();
+    for name in names {
+        visit(name, types, &mut visited, &mut on_path, &mut out);
+    }
+    out
+}
+
+fn render_decl(name: &str, decl: &Decl, out: &mut dyn Write) -> io::Result<()> {
+    match decl {
+        Decl::Typedef(ty) => write!(out, "pub type {} = {};\n\n", name, to_rust_type(ty)),
+        Decl::Enum { variants, .. } => {
+            writeln!(out, "#[repr(C)]\npub enum {} {{", name)?;
+            for variant in variants {
+                match variant.value {
+                    Some(ref v) => writeln!(out, "\t{}={},", variant.ident, v)?,
+                    None => writeln!(out, "\t{},", variant.ident)?,
                 }
-                println!("}}\n");
             }
-        }
-        true
-    } else {
-        println!("Not found: {}", name);
-        false
+            writeln!(out, "}}\n")
+        },
+        Decl::Struct { fields, .. } => emit_struct_like("struct", name, fields, out),
+        Decl::Union { fields, .. } => emit_struct_like("union", name, fields, out),
+        Decl::Function { .. } | Decl::Extern(_) => Ok(()),
     }
 }
 
-static KNONW_ALIASES: &'static [(&'static str, &'static str)] = &[
-    ("uint32_T", "u32"), ("int32_T", "i32"), ("boolean_T", "u8"), ("uint16_T", "u16"), ("real_T", "f64")];
+fn render_param(param: &Param) -> String {
+    let name = param.name.as_deref().unwrap_or("_");
+    format!("{}: {}", name, to_rust_type(&param.ty))
+}
 
-fn main() {
-    // file created with cl /P <header file>
-    if let Ok(mut types) = parse(r"hdf5.i") {
-        for &(name, alias) in KNONW_ALIASES {
-            types.insert(name.into(), Stmt::Alias(alias.into()));
+/// Groups every `Decl::Function` and `Decl::Extern` in `types` into a
+/// single `extern "C"` block of `pub fn` signatures and `pub static mut`
+/// declarations, sorted by name so the block doesn't depend on `HashMap`
+/// iteration order.
+fn emit_functions(types: &HashMap<String, Decl>, out: &mut dyn Write) -> io::Result<()> {
+    let mut functions: Vec<_> = types.iter().filter_map(|(name, decl)| match decl {
+        Decl::Function { ret, params, variadic } => Some((name, ret, params, *variadic)),
+        _ => None,
+    }).collect();
+    functions.sort_by_key(|&(name, ..)| name);
+
+    let mut externs: Vec<_> = types.iter().filter_map(|(name, decl)| match decl {
+        Decl::Extern(ty) => Some((name, ty)),
+        _ => None,
+    }).collect();
+    externs.sort_by_key(|&(name, _)| name);
+
+    if functions.is_empty() && externs.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out, "extern \"C\" {{")?;
+    for (name, ty) in externs {
+        writeln!(out, "\tpub static mut {}: {};", name, to_rust_type(ty))?;
+    }
+    for (name, ret, params, variadic) in functions {
+        let mut args: Vec<String> = params.iter().map(render_param).collect();
+        if variadic {
+            args.push("...".to_string());
+        }
+        match is_void(ret) {
+            true => writeln!(out, "\tpub fn {}({});", name, args.join(", "))?,
+            false => writeln!(out, "\tpub fn {}({}) -> {};", name, args.join(", "), to_rust_type(ret))?,
         }
-        let mut known = HashSet::new();
+    }
+    writeln!(out, "}}\n")
+}
 
-        println!("#![allow(non_camel_case_types)]");
-        println!("#![allow(dead_code)]");
-        println!("#![allow(non_snake_case)]");
+/// Renders an [`Ast`] as Rust source into `out`, in a deterministic,
+/// dependency-ordered sequence so the same header always produces
+/// byte-identical output. `out` is a trait object so callers can emit to a
+/// file, a `Vec<u8>` for snapshot tests, or anywhere else that implements
+/// `Write`.
+pub fn emit(ast: &Ast, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "#![allow(non_camel_case_types)]")?;
+    writeln!(out, "#![allow(dead_code)]")?;
+    writeln!(out, "#![allow(non_snake_case)]")?;
+    writeln!(out)?;
+
+    for name in dependency_order(&ast.decls) {
+        render_decl(&name, &ast.decls[&name], out)?;
+    }
+
+    emit_functions(&ast.decls, out)
+}
 
-        println!();
+fn main() {
+    // file created with cl /P <header file>
+    if let Ok(ast) = parse_header(r"hdf5.i") {
+        let stdout = io::stdout();
+        emit(&ast, &mut stdout.lock()).expect("writing to stdout");
 
-        for k in types.keys() {
-            lookup(k, &types, &mut known);
+        for diagnostic in &ast.diagnostics {
+            eprintln!("{}", diagnostic.render(&ast.source, &ast.line_starts));
         }
     }
 }
@@ -476,13 +1167,394 @@ fn main() {
 #[cfg(test)]
 mod tests {
 
-    use super::Tokenizer;
+    use super::*;
 
     #[test]
     fn it_works() {
         let tokenizer = Tokenizer::new("abc def = == ! != xyz ... < << <<= > >> >>=");
-        for token in tokenizer {
+        for (token, _span) in tokenizer {
             println!("{}", token);
         }
     }
+
+    #[test]
+    fn unterminated_string_reports_diagnostic_instead_of_panicking() {
+        let mut tokenizer = Tokenizer::new("\"abc");
+        let tokens: Vec<_> = tokenizer.by_ref().collect();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokenizer.diagnostics.len(), 1);
+        assert_eq!(tokenizer.diagnostics[0].message, "unterminated string literal");
+    }
+
+    #[test]
+    fn line_col_maps_offsets_against_rebuilt_source() {
+        let line_starts = [0, 5, 11];
+        assert_eq!(Diagnostic::line_col(0, &line_starts), (0, 0));
+        assert_eq!(Diagnostic::line_col(7, &line_starts), (1, 2));
+    }
+
+    #[test]
+    fn render_draws_a_caret_line_under_the_offending_span() {
+        let source = "int x;\n\"abc";
+        let line_starts = [0, 7];
+        let diagnostic = Diagnostic::error(Span::new(7, 11), "unterminated string literal");
+        let rendered = diagnostic.render(source, &line_starts);
+        assert_eq!(rendered, "error: unterminated string literal\n\"abc\n^^^^");
+    }
+
+    #[test]
+    fn render_strips_the_leading_cr_parse_header_rebuilds_each_line_with() {
+        // Goes through the real parse_header path (which rebuilds `source`
+        // by prefixing every line with `\r`) instead of a hand-built
+        // `source`/`line_starts` fixture, so a regression in how `render`
+        // accounts for that `\r` actually shows up here.
+        let path = std::env::temp_dir().join("cparser_render_cr_test.i");
+        std::fs::write(&path, "typedef int foo;\n\"abc").unwrap();
+        let ast = parse_header(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(ast.diagnostics.len(), 1);
+        let rendered = ast.diagnostics[0].render(&ast.source, &ast.line_starts);
+        assert_eq!(rendered, "error: unterminated string literal\n\"abc\n^^^^");
+    }
+
+    #[test]
+    fn parses_pointer_and_array_members() {
+        let fields = parse_member_list(&["char", "*", "name", ";", "int", "count", "[", "4", "]"]);
+        assert!(matches!(fields[0].ty, Type::Pointer(_)));
+        assert!(matches!(fields[1].ty, Type::Array(_, _)));
+    }
+
+    #[test]
+    fn parses_function_pointer_member() {
+        let fields = parse_member_list(&["void", "(", "*", "cb", ")", "(", "int", ")"]);
+        assert_eq!(fields[0].ident, "cb");
+        assert!(matches!(fields[0].ty, Type::FunctionPointer { .. }));
+    }
+
+    #[test]
+    fn parses_typedef_struct() {
+        let tokens = ["typedef", "struct", "{", "int", "x", ";", "}", "Point"];
+        let (name, decl) = Parser::new(&tokens).parse_typedef().unwrap();
+        assert_eq!(name, "Point");
+        assert!(matches!(decl, Decl::Struct { .. }));
+    }
+
+    #[test]
+    fn self_referential_tagged_typedef_registers_an_alias_under_its_tag() {
+        // typedef struct Node_ { struct Node_ *next; } Node;
+        let mut types = HashMap::new();
+        let mut diagnostics = Vec::new();
+        let stmt = [
+            "typedef", "struct", "Node_", "{", "struct", "Node_", "*", "next", ";", "}", "Node",
+        ];
+        let spans = [Span::new(0, 0); 11];
+        parse_statement(&stmt, &spans, &mut types, &mut diagnostics);
+
+        assert!(matches!(types.get("Node"), Some(Decl::Struct { .. })));
+        match types.get("Node_") {
+            Some(Decl::Typedef(Type::Named(n))) => assert_eq!(n, "Node"),
+            other => panic!("expected an alias typedef to Node, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn to_rust_type_strips_the_struct_union_enum_keyword_off_a_named_type() {
+        assert_eq!(to_rust_type(&Type::Named("struct Node_".into())), "Node_");
+        assert_eq!(to_rust_type(&Type::Named("union Value_".into())), "Value_");
+        assert_eq!(to_rust_type(&Type::Named("enum Color_".into())), "Color_");
+    }
+
+    #[test]
+    fn translates_nested_pointers_to_mut_chains() {
+        let ty = parse_type_tokens(&["char", "*", "*"]);
+        assert_eq!(to_rust_type(&ty), "*mut *mut std::os::raw::c_char");
+    }
+
+    #[test]
+    fn translates_const_pointee_to_const_pointer() {
+        let ty = parse_type_tokens(&["const", "void", "*"]);
+        assert_eq!(to_rust_type(&ty), "*const std::os::raw::c_void");
+    }
+
+    #[test]
+    fn drops_pointer_variable_level_const_but_keeps_pointee_const() {
+        let ty = parse_type_tokens(&["const", "int", "*", "const", "*"]);
+        assert_eq!(to_rust_type(&ty), "*mut *const std::os::raw::c_int");
+    }
+
+    #[test]
+    fn parses_named_and_variadic_params() {
+        let (params, variadic) = parse_param_list(&["const", "char", "*", "fmt", ",", "..."]);
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[0].name.as_deref(), Some("fmt"));
+        assert!(variadic);
+    }
+
+    #[test]
+    fn lone_void_param_means_no_parameters() {
+        let (params, variadic) = parse_param_list(&["void"]);
+        assert!(params.is_empty());
+        assert!(!variadic);
+    }
+
+    #[test]
+    fn function_pointer_param_names_are_stripped_from_the_translated_type() {
+        // void register_cb(void (*cb)(int a, int b));
+        let (params, _) = parse_param_list(&[
+            "void", "(", "*", "cb", ")", "(", "int", "a", ",", "int", "b", ")",
+        ]);
+        assert_eq!(to_rust_type(&params[0].ty), "Option<extern \"C\" fn(std::os::raw::c_int, std::os::raw::c_int)>");
+    }
+
+    #[test]
+    fn function_pointer_typedef_strips_named_params_too() {
+        // typedef void (*Callback)(int code, void *ctx);
+        let tokens = [
+            "typedef", "void", "(", "*", "Callback", ")", "(", "int", "code", ",", "void", "*", "ctx", ")",
+        ];
+        let (name, decl) = Parser::new(&tokens).parse_typedef().unwrap();
+        assert_eq!(name, "Callback");
+        match decl {
+            Decl::Typedef(ty) => assert_eq!(
+                to_rust_type(&ty),
+                "Option<extern \"C\" fn(std::os::raw::c_int, *mut std::os::raw::c_void)>"
+            ),
+            other => panic!("expected a Typedef decl, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn function_pointer_param_keeps_its_own_comma_separated_args_intact() {
+        // void register_cb(void (*cb)(int, int));
+        let (params, variadic) = parse_param_list(&[
+            "void", "(", "*", "cb", ")", "(", "int", ",", "int", ")",
+        ]);
+        assert!(!variadic);
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[0].name.as_deref(), Some("cb"));
+        match &params[0].ty {
+            Type::FunctionPointer { ret, params } => {
+                assert!(matches!(**ret, Type::Named(ref n) if n == "void"));
+                assert_eq!(params.len(), 2);
+            },
+            other => panic!("expected a function pointer param, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unnamed_multi_word_builtin_params_are_not_mistaken_for_named_params() {
+        // int foo(unsigned long);
+        let (params, _) = parse_param_list(&["unsigned", "long"]);
+        assert_eq!(params[0].name, None);
+        assert_eq!(to_rust_type(&params[0].ty), "std::os::raw::c_ulong");
+
+        // int foo(signed char);
+        let (params, _) = parse_param_list(&["signed", "char"]);
+        assert_eq!(params[0].name, None);
+        assert_eq!(to_rust_type(&params[0].ty), "std::os::raw::c_schar");
+
+        // int foo(long int);
+        let (params, _) = parse_param_list(&["long", "int"]);
+        assert_eq!(params[0].name, None);
+        assert_eq!(to_rust_type(&params[0].ty), "std::os::raw::c_long");
+    }
+
+    #[test]
+    fn void_return_type_is_omitted_rather_than_translated_to_c_void() {
+        let ty = parse_type_tokens(&["void"]);
+        assert_eq!(to_rust_type(&Type::FunctionPointer { ret: Box::new(ty), params: vec![] }), "Option<extern \"C\" fn()>");
+
+        let ret = parse_type_tokens(&["void"]);
+        let params = vec![Param { name: None, ty: parse_type_tokens(&["int"]) }];
+        let mut types = HashMap::new();
+        types.insert("varfn".to_string(), Decl::Function { ret, params, variadic: true });
+        let mut out = Vec::new();
+        emit_functions(&types, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "extern \"C\" {\n\tpub fn varfn(_: std::os::raw::c_int, ...);\n}\n\n");
+    }
+
+    #[test]
+    fn extern_prefixed_function_is_registered_without_extern_in_the_return_type() {
+        let mut types = HashMap::new();
+        let mut diagnostics = Vec::new();
+        let stmt = ["extern", "int", "foo", "(", "int", "x", ")"];
+        let spans = [Span::new(0, 0); 7];
+        parse_statement(&stmt, &spans, &mut types, &mut diagnostics);
+
+        match types.get("foo") {
+            Some(Decl::Function { ret, params, variadic }) => {
+                assert!(matches!(ret, Type::Named(n) if n == "int"));
+                assert_eq!(params.len(), 1);
+                assert!(!variadic);
+            },
+            other => panic!("expected a Function decl, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extern_variable_declaration_is_registered_as_extern_decl() {
+        let mut types = HashMap::new();
+        let mut diagnostics = Vec::new();
+        let stmt = ["extern", "char", "*", "progname"];
+        let spans = [Span::new(0, 0); 4];
+        parse_statement(&stmt, &spans, &mut types, &mut diagnostics);
+
+        match types.get("progname") {
+            Some(Decl::Extern(ty)) => assert_eq!(to_rust_type(ty), "*mut std::os::raw::c_char"),
+            other => panic!("expected an Extern decl, got {:?}", other),
+        }
+
+        let mut out = Vec::new();
+        emit_functions(&types, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "extern \"C\" {\n\tpub static mut progname: *mut std::os::raw::c_char;\n}\n\n"
+        );
+    }
+
+    #[test]
+    fn bare_tagged_body_decl_without_a_typedef_name_is_ignored() {
+        // struct Bar { int x; };
+        let mut types = HashMap::new();
+        let mut diagnostics = Vec::new();
+        let stmt = ["struct", "Bar", "{", "int", "x", ";", "}"];
+        let spans = [Span::new(0, 0); 7];
+        parse_statement(&stmt, &spans, &mut types, &mut diagnostics);
+
+        assert!(types.is_empty());
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn bare_forward_declaration_without_a_body_is_also_ignored() {
+        // struct Foo;
+        let mut types = HashMap::new();
+        let mut diagnostics = Vec::new();
+        let stmt = ["struct", "Foo"];
+        let spans = [Span::new(0, 0); 2];
+        parse_statement(&stmt, &spans, &mut types, &mut diagnostics);
+
+        assert!(types.is_empty());
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn struct_returning_function_declarator_is_not_mistaken_for_a_bare_tagged_body_decl() {
+        // struct Foo *make_foo(void);
+        let mut types = HashMap::new();
+        let mut diagnostics = Vec::new();
+        let stmt = ["struct", "Foo", "*", "make_foo", "(", "void", ")"];
+        let spans = [Span::new(0, 0); 7];
+        parse_statement(&stmt, &spans, &mut types, &mut diagnostics);
+
+        match types.get("make_foo") {
+            Some(Decl::Function { ret, .. }) => assert!(matches!(ret, Type::Pointer(_))),
+            other => panic!("expected a Function decl, got {:?}", other),
+        }
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn parses_typedef_union() {
+        let tokens = ["typedef", "union", "{", "int", "i", ";", "float", "f", ";", "}", "Value"];
+        let (name, decl) = Parser::new(&tokens).parse_typedef().unwrap();
+        assert_eq!(name, "Value");
+        assert!(matches!(decl, Decl::Union { .. }));
+    }
+
+    #[test]
+    fn parses_bitfield_member() {
+        let fields = parse_member_list(&["unsigned", "flags", ":", "3"]);
+        assert_eq!(fields[0].ident, "flags");
+        assert_eq!(fields[0].bits, Some(3));
+    }
+
+    #[test]
+    fn packs_consecutive_same_type_bitfields_into_one_storage_unit() {
+        let fields = parse_member_list(&["unsigned", "a", ":", "3", ";", "unsigned", "b", ":", "5"]);
+        assert_eq!(fields.len(), 2);
+        assert_eq!(bit_width_of(&to_rust_type(&fields[0].ty)), 32);
+
+        let mut out = Vec::new();
+        emit_struct_like("struct", "S", &fields, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\tpub _bitfield0: std::os::raw::c_uint,"));
+        assert!(!out.contains("_bitfield1"));
+    }
+
+    #[test]
+    fn parses_anonymous_bitfield_with_no_accessor_identifier() {
+        // unsigned : 4; is the standard padding/alignment idiom: it occupies
+        // bits but has no name to generate an accessor for.
+        let fields = parse_member_list(&["unsigned", ":", "4"]);
+        assert_eq!(fields[0].ident, "");
+        assert_eq!(fields[0].bits, Some(4));
+    }
+
+    #[test]
+    fn signed_bitfield_getter_sign_extends() {
+        // int sflag : 3; stored in bits 0..3 as the bit pattern for -1.
+        let accessor = render_bitfield_accessor("_bitfield0", "std::os::raw::c_int", "sflag", 0, 3);
+        assert!(accessor.contains("<< 29 >> 29"));
+    }
+
+    #[test]
+    fn unsigned_bitfield_getter_does_not_sign_extend() {
+        let accessor = render_bitfield_accessor("_bitfield0", "std::os::raw::c_uint", "flags", 0, 3);
+        assert!(!accessor.contains(">> 29"));
+    }
+
+    #[test]
+    fn oversized_bitfield_is_dropped_with_a_diagnostic() {
+        // struct { unsigned big : 40; };
+        let mut diagnostics = Vec::new();
+        let fields = vec![Field { ident: "big".into(), ty: parse_type_tokens(&["unsigned"]), bits: Some(40) }];
+        let span = Span::new(0, 0);
+        let fields = validate_bitfields(fields, span, &mut diagnostics);
+
+        assert!(fields.is_empty());
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn dependency_order_emits_referenced_types_first() {
+        let mut types = HashMap::new();
+        types.insert("Point".to_string(), Decl::Struct { tag: None, fields: vec![
+            Field { ident: "x".into(), ty: Type::Named("int".into()), bits: None },
+        ]});
+        types.insert("Line".to_string(), Decl::Struct { tag: None, fields: vec![
+            Field { ident: "start".into(), ty: Type::Named("Point".into()), bits: None },
+        ]});
+
+        let order = dependency_order(&types);
+        let point = order.iter().position(|n| n == "Point").unwrap();
+        let line = order.iter().position(|n| n == "Line").unwrap();
+        assert!(point < line);
+    }
+
+    #[test]
+    fn dependency_order_breaks_self_referential_pointer_cycles() {
+        let mut types = HashMap::new();
+        types.insert("Node".to_string(), Decl::Struct { tag: None, fields: vec![
+            Field { ident: "next".into(), ty: Type::Pointer(Box::new(Type::Named("Node".into()))), bits: None },
+        ]});
+
+        let order = dependency_order(&types);
+        assert_eq!(order, vec!["Node".to_string()]);
+    }
+
+    #[test]
+    fn emit_is_deterministic_across_runs() {
+        let mut decls = HashMap::new();
+        decls.insert("Foo".to_string(), Decl::Typedef(Type::Named("int".into())));
+        decls.insert("Bar".to_string(), Decl::Typedef(Type::Named("char".into())));
+        let ast = Ast { decls, diagnostics: Vec::new(), source: String::new(), line_starts: vec![0] };
+
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        emit(&ast, &mut first).unwrap();
+        emit(&ast, &mut second).unwrap();
+        assert_eq!(first, second);
+    }
 }